@@ -21,3 +21,450 @@ fn in_loop_perfect_conditions() {
 		machine.fire(Event::Ein(line, high)).unwrap();
 	}
 }
+
+/// A machine whose whole [`Engine`](turbostate::Engine) impl is generated by the
+/// `state_machine!` macro, exercised through the same `fire` entry point as the
+/// hand-written ones.
+///
+/// The `@assert` blocks the macro emits turn a typo in any state name into a
+/// compile error pointing at the offending arm; that is checked at build time,
+/// so here we only confirm a well-formed table drives transitions as written.
+mod macro_built {
+	use std::sync::Arc;
+	use std::sync::Mutex;
+
+	use turbostate::state_machine;
+	use turbostate::Machine;
+
+	struct Gate;
+
+	#[derive(Debug, Default)]
+	enum State {
+		#[default]
+		Idle,
+		Running,
+		Done,
+	}
+
+	#[derive(Debug)]
+	enum Event {
+		Go,
+		Stop,
+	}
+
+	#[derive(Debug, Default)]
+	struct Shared;
+
+	type Error = ();
+
+	use Event::*;
+	use State::*;
+
+	state_machine! {
+		engine: Gate,
+		state: State,
+		event: Event,
+		error: Error,
+		shared: Shared,
+		bindings: (state, event, shared),
+
+		Idle + Go => Running,
+		Running + Stop => Done,
+	}
+
+	#[test]
+	fn generated_impl_drives_transitions() {
+		let visited = Arc::new(Mutex::new(Vec::new()));
+		let log = visited.clone();
+		let machine = Machine::<Gate>::default_shared(Shared)
+			.with_checkpoint(move |state, _| log.lock().unwrap().push(format!("{state:?}")));
+
+		machine.fire(Go).unwrap();
+		machine.fire(Stop).unwrap();
+		// The unlisted `(Done, Stop)` falls through to the generated `_ => Pass`.
+		machine.fire(Stop).unwrap();
+
+		assert_eq!(*visited.lock().unwrap(), ["Running", "Done"]);
+	}
+}
+
+/// Persistence: the checkpoint hook fires after every transition, and (with the
+/// `serde` feature) a [`Snapshot`](turbostate::Snapshot) round-trips back into a
+/// machine in the same state.
+mod persistence {
+	use std::sync::Arc;
+	use std::sync::Mutex;
+
+	use turbostate::Engine;
+	use turbostate::Flow;
+	use turbostate::Machine;
+
+	struct Counter;
+
+	#[derive(Debug, Clone, Default, PartialEq)]
+	#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+	enum State {
+		#[default]
+		Off,
+		On,
+	}
+
+	#[derive(Debug)]
+	enum Event {
+		Toggle,
+	}
+
+	#[derive(Debug, Clone, Default, PartialEq)]
+	#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+	struct Shared {
+		toggles: u32,
+	}
+
+	impl Engine for Counter {
+		type State = State;
+		type Event = Event;
+		type Error = ();
+		type Shared = Shared;
+
+		fn next(state: &State, _event: Event, shared: &mut Shared) -> Flow<State, (), Event> {
+			shared.toggles += 1;
+			match state {
+				State::Off => Flow::Transition(State::On),
+				State::On => Flow::Transition(State::Off),
+			}
+		}
+	}
+
+	#[test]
+	fn checkpoint_runs_after_every_transition() {
+		let seen = Arc::new(Mutex::new(Vec::new()));
+		let log = seen.clone();
+		let machine = Machine::<Counter>::default_shared(Shared::default())
+			.with_checkpoint(move |state, shared| log.lock().unwrap().push((state.clone(), shared.toggles)));
+
+		machine.fire(Event::Toggle).unwrap();
+		machine.fire(Event::Toggle).unwrap();
+
+		assert_eq!(*seen.lock().unwrap(), [(State::On, 1), (State::Off, 2)]);
+	}
+
+	#[cfg(feature = "serde")]
+	#[test]
+	fn snapshot_restores_state_and_shared() {
+		let machine = Machine::<Counter>::default_shared(Shared::default());
+		machine.fire(Event::Toggle).unwrap();
+
+		let snapshot = machine.snapshot();
+		assert_eq!(snapshot.state, State::On);
+		assert_eq!(snapshot.shared.toggles, 1);
+
+		// A full serialize/deserialize round-trip, as a crash-recovery path would do.
+		let json = serde_json::to_string(&snapshot).unwrap();
+		let decoded: turbostate::Snapshot<Counter> = serde_json::from_str(&json).unwrap();
+
+		let restored = Machine::<Counter>::restore(decoded);
+		let again = restored.snapshot();
+		assert_eq!(again.state, State::On);
+		assert_eq!(again.shared.toggles, 1);
+	}
+}
+
+/// The priority work-queue behind `fire`: [`Flow::Dispatch`] follow-ups run
+/// highest-priority-first, ties keep enqueue order (FIFO), and a
+/// [`Flow::Failure`] clears whatever is still pending.
+mod dispatch {
+	use std::sync::Arc;
+	use std::sync::Mutex;
+
+	use turbostate::Engine;
+	use turbostate::Flow;
+	use turbostate::Machine;
+
+	struct Queue;
+
+	#[derive(Debug, Default)]
+	enum State {
+		#[default]
+		Start,
+		Done,
+	}
+
+	#[derive(Debug)]
+	enum Event {
+		/// Fan out a spread of prioritized ticks.
+		Seed,
+		/// Fan out equal-priority ticks with a failing one in the middle.
+		SeedFail,
+		Tick(u32),
+		Boom,
+	}
+
+	/// Records, in processing order, every tick the queue hands to `next`.
+	#[derive(Debug, Default)]
+	struct Shared {
+		order: Arc<Mutex<Vec<u32>>>,
+	}
+
+	impl Engine for Queue {
+		type State = State;
+		type Event = Event;
+		type Error = ();
+		type Shared = Shared;
+
+		fn next(_state: &State, event: Event, shared: &mut Shared) -> Flow<State, (), Event> {
+			match event {
+				Event::Seed => Flow::Dispatch(State::Done, vec![
+					(1, Event::Tick(1)),
+					(5, Event::Tick(5)),
+					(1, Event::Tick(2)),
+				]),
+				Event::SeedFail => Flow::Dispatch(State::Done, vec![
+					(10, Event::Tick(1)),
+					(10, Event::Tick(2)),
+					(10, Event::Boom),
+					(10, Event::Tick(3)),
+				]),
+				Event::Tick(n) => {
+					shared.order.lock().unwrap().push(n);
+					Flow::Pass
+				}
+				Event::Boom => Flow::Failure(()),
+			}
+		}
+	}
+
+	#[test]
+	fn dispatch_orders_by_priority_then_fifo() {
+		let order = Arc::new(Mutex::new(Vec::new()));
+		let machine = Machine::<Queue>::new_shared(State::Start, Shared { order: order.clone() });
+
+		machine.fire(Event::Seed).unwrap();
+
+		// Priority 5 first; the two priority-1 ticks keep their enqueue order.
+		assert_eq!(*order.lock().unwrap(), [5, 1, 2]);
+	}
+
+	#[test]
+	fn failure_clears_pending_events() {
+		let order = Arc::new(Mutex::new(Vec::new()));
+		let machine = Machine::<Queue>::new_shared(State::Start, Shared { order: order.clone() });
+
+		machine.fire(Event::SeedFail).unwrap_err();
+
+		// Ticks 1 and 2 ran before `Boom`; tick 3 was dropped with the queue.
+		assert_eq!(*order.lock().unwrap(), [1, 2]);
+	}
+}
+
+/// The non-async driver: [`drive`](turbostate::Machine::drive) fires a sequence
+/// in iteration order and stops on the first event that fails, propagating its
+/// error — the ordering guarantee the request is about.
+mod driver {
+	use std::sync::Arc;
+	use std::sync::Mutex;
+
+	use turbostate::Engine;
+	use turbostate::Flow;
+	use turbostate::Machine;
+
+	struct Tape;
+
+	#[derive(Debug, Default)]
+	enum State {
+		#[default]
+		Run,
+	}
+
+	#[derive(Debug)]
+	enum Event {
+		Push(u32),
+		Fail,
+	}
+
+	#[derive(Debug, Default)]
+	struct Shared {
+		seen: Arc<Mutex<Vec<u32>>>,
+	}
+
+	impl Engine for Tape {
+		type State = State;
+		type Event = Event;
+		type Error = u32;
+		type Shared = Shared;
+
+		fn next(_state: &State, event: Event, shared: &mut Shared) -> Flow<State, u32, Event> {
+			match event {
+				Event::Push(n) => {
+					shared.seen.lock().unwrap().push(n);
+					Flow::Pass
+				}
+				Event::Fail => Flow::Failure(42),
+			}
+		}
+	}
+
+	#[test]
+	fn drive_fires_in_iteration_order() {
+		let seen = Arc::new(Mutex::new(Vec::new()));
+		let machine = Machine::<Tape>::new_shared(State::Run, Shared { seen: seen.clone() });
+
+		machine
+			.drive([Event::Push(1), Event::Push(2), Event::Push(3)])
+			.unwrap();
+
+		assert_eq!(*seen.lock().unwrap(), [1, 2, 3]);
+	}
+
+	#[test]
+	fn drive_stops_and_propagates_on_failure() {
+		let seen = Arc::new(Mutex::new(Vec::new()));
+		let machine = Machine::<Tape>::new_shared(State::Run, Shared { seen: seen.clone() });
+
+		let err = machine
+			.drive([Event::Push(1), Event::Fail, Event::Push(2)])
+			.unwrap_err();
+
+		assert_eq!(err, 42);
+		// The event after the failing one is never fired.
+		assert_eq!(*seen.lock().unwrap(), [1]);
+	}
+}
+
+/// The `tracing` instrumentation: a capturing layer confirms each `transition`
+/// span carries `from_state`/`event` on entry and `to_state`/`flow` on
+/// completion, and that a `Slide` cascade parents its follow-up span into the
+/// one that enqueued it.
+#[cfg(feature = "tracing")]
+mod instrumentation {
+	use std::collections::HashMap;
+	use std::sync::Arc;
+	use std::sync::Mutex;
+
+	use tracing_subscriber::prelude::*;
+	use turbostate::Engine;
+	use turbostate::Flow;
+	use turbostate::Machine;
+
+	struct Cascade;
+
+	#[derive(Debug, Default)]
+	enum State {
+		#[default]
+		A,
+		B,
+		C,
+	}
+
+	#[derive(Debug)]
+	enum Event {
+		Go,
+		Tick,
+	}
+
+	#[derive(Debug, Default)]
+	struct Shared;
+
+	impl Engine for Cascade {
+		type State = State;
+		type Event = Event;
+		type Error = ();
+		type Shared = Shared;
+
+		fn next(state: &State, event: Event, _shared: &mut Shared) -> Flow<State, (), Event> {
+			match (state, event) {
+				(State::A, Event::Go) => Flow::Slide(State::B, Event::Tick),
+				(State::B, Event::Tick) => Flow::Transition(State::C),
+				_ => Flow::Pass,
+			}
+		}
+	}
+
+	/// One captured span: its id, its explicit parent (if any) and the fields
+	/// recorded on it, collected in creation order.
+	#[derive(Default)]
+	struct Rec {
+		id: u64,
+		parent: Option<u64>,
+		fields: HashMap<String, String>,
+	}
+
+	struct Visitor<'a>(&'a mut HashMap<String, String>);
+
+	impl tracing::field::Visit for Visitor<'_> {
+		fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+			self.0.insert(field.name().to_owned(), format!("{value:?}"));
+		}
+
+		fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+			self.0.insert(field.name().to_owned(), value.to_owned());
+		}
+	}
+
+	#[derive(Clone)]
+	struct Capture {
+		spans: Arc<Mutex<Vec<Rec>>>,
+	}
+
+	impl<S> tracing_subscriber::Layer<S> for Capture
+	where
+		S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+	{
+		fn on_new_span(
+			&self,
+			attrs: &tracing::span::Attributes<'_>,
+			id: &tracing::span::Id,
+			_ctx: tracing_subscriber::layer::Context<'_, S>,
+		) {
+			let mut fields = HashMap::new();
+			attrs.record(&mut Visitor(&mut fields));
+			self.spans.lock().unwrap().push(Rec {
+				id: id.into_u64(),
+				parent: attrs.parent().map(tracing::span::Id::into_u64),
+				fields,
+			});
+		}
+
+		fn on_record(
+			&self,
+			id: &tracing::span::Id,
+			values: &tracing::span::Record<'_>,
+			_ctx: tracing_subscriber::layer::Context<'_, S>,
+		) {
+			let raw = id.into_u64();
+			let mut spans = self.spans.lock().unwrap();
+			if let Some(rec) = spans.iter_mut().find(|rec| rec.id == raw) {
+				values.record(&mut Visitor(&mut rec.fields));
+			}
+		}
+	}
+
+	#[test]
+	fn fire_records_transition_spans_as_a_tree() {
+		let spans = Arc::new(Mutex::new(Vec::new()));
+		let subscriber = tracing_subscriber::registry().with(Capture { spans: spans.clone() });
+
+		tracing::subscriber::with_default(subscriber, || {
+			let machine = Machine::<Cascade>::default_shared(Shared);
+			machine.fire(Event::Go).unwrap();
+		});
+
+		let spans = spans.lock().unwrap();
+		assert_eq!(spans.len(), 2, "one span per fired event");
+
+		// The seed transition: recorded on entry and on completion, rooted.
+		let root = &spans[0];
+		assert_eq!(root.fields["from_state"], "A");
+		assert_eq!(root.fields["event"], "Go");
+		assert_eq!(root.fields["flow"], "Slide");
+		assert_eq!(root.fields["to_state"], "B");
+		assert_eq!(root.parent, None);
+
+		// The `Slide` follow-up: a child of the span that enqueued it.
+		let child = &spans[1];
+		assert_eq!(child.fields["from_state"], "B");
+		assert_eq!(child.fields["event"], "Tick");
+		assert_eq!(child.fields["flow"], "Transition");
+		assert_eq!(child.fields["to_state"], "C");
+		assert_eq!(child.parent, Some(root.id));
+	}
+}
@@ -15,11 +15,26 @@
 #![feature(decl_macro)]
 #![feature(try_trait_v2)]
 
+use std::collections::BinaryHeap;
 use std::marker::PhantomData;
 use std::ops::FromResidual;
 use std::sync::Arc;
 use std::sync::Mutex;
 
+#[cfg(feature = "async")]
+use std::future::Future;
+#[cfg(feature = "async")]
+use futures::stream::Stream;
+#[cfg(feature = "async")]
+use futures::stream::StreamExt;
+
+/// Priority of a follow-up event queued by a transition.
+///
+/// Higher values are dispatched first; events of equal priority keep the order
+/// in which they were enqueued. The [`Flow::Slide`] shorthand enqueues at
+/// priority `0`.
+pub type Priority = u32;
+
 /// `Flow` represents the possible outcomes of state transitions in the state machine.
 #[derive(Debug)]
 pub enum Flow<T, E, B> {
@@ -28,7 +43,14 @@ pub enum Flow<T, E, B> {
 	/// Transition to a new state.
 	Transition(T),
 	/// Jump to another branch within the same event, specifying a new state and event.
+	///
+	/// Shorthand for a single priority-`0` [`Dispatch`](Self::Dispatch) follow-up.
 	Slide(T, B),
+	/// Transition to a new state and enqueue several prioritized follow-up events.
+	///
+	/// The follow-ups are processed highest-priority-first, before control
+	/// returns to the caller of [`Machine::fire`].
+	Dispatch(T, Vec<(Priority, B)>),
 	/// Raise an error if an error occurs during the transition.
 	Failure(E),
 }
@@ -83,6 +105,186 @@ pub trait Engine {
 	}
 }
 
+/// Declaratively builds an [`Engine`] implementation from a transition table.
+///
+/// Instead of hand-writing the whole `match (state, event)` body inside
+/// [`Engine::next`], you describe the graph once and let the macro emit the
+/// implementation. Each arm has the shape
+///
+/// ```text
+/// <from> + <event pattern> [if <guard>] => <action>
+/// ```
+///
+/// where `<action>` is either a bare target state — shorthand for
+/// [`Flow::Transition`] — or an arbitrary expression evaluating to a [`Flow`].
+/// The optional `if <guard>` becomes a real `match` guard, so a transition whose
+/// guard fails falls through to the next matching arm (the usual
+/// guarded-then-default idiom works). A trailing `_ => Flow::Pass` arm is
+/// appended automatically, so only the interesting transitions have to be listed.
+///
+/// States are fieldless enum variants, matching the machines in this workspace.
+///
+/// For every state mentioned on the left — and every bare target on the right —
+/// the macro emits a `const _: fn() = || { let _: State = State::Variant; };`
+/// assertion, so a typo in a state name fails to compile pointing at the
+/// offending arm rather than silently producing a dead transition.
+///
+/// The `bindings` entry names the `next` parameters; event patterns, guards and
+/// actions see `state`, `event` and `shared` through those names, exactly as a
+/// hand-written [`Engine::next`] would. The machine's states and events must be
+/// in scope at the call site (`use State::*; use Event::*;`), just like the
+/// hand-written body `use`s them.
+///
+/// # Example
+///
+/// ```ignore
+/// use State::*;
+/// use Event::*;
+///
+/// state_machine! {
+///     engine: In,
+///     state: State,
+///     event: Event,
+///     error: Error,
+///     shared: Shared,
+///     bindings: (state, event, shared),
+///
+///     Idle + Ein(1, true) | Rush => Entry,
+///     Entry + Ein(3, true) => Finalizing,
+///     Finalizing + Ein(3, false) if shared.hold => WaitRelease,
+///     Finalizing + Ein(3, false) => Flow::Transition(Idle),
+///     WaitRelease + Ein(2, false) => Flow::Transition(Idle),
+/// }
+/// ```
+pub macro state_machine {
+	// Public entry point: peel off the header and hand the arms to the muncher.
+	//
+	// `bindings` names the `(state, event, shared)` parameters as the caller sees
+	// them, so guards and actions can mention `shared` directly. The machine's
+	// states and events have to be in scope at the call site (e.g. `use State::*;`),
+	// exactly as a hand-written `next` would `use` them.
+	(
+		engine: $engine:ty,
+		state: $state:ident,
+		event: $event:ident,
+		error: $error:ty,
+		shared: $shared:ty,
+		bindings: ($sb:ident, $eb:ident, $shb:ident),
+		$($arms:tt)*
+	) => {
+		$crate::state_machine! {
+			@munch [$engine] [$state] [$event] [$error] [$shared] [$sb] [$eb] [$shb]
+			{ } { }
+			$($arms)*
+		}
+	},
+
+	// No arms left: emit the collected assertions and the `impl`.
+	(
+		@munch [$engine:ty] [$state:ident] [$event:ident] [$error:ty] [$shared:ty]
+		[$sb:ident] [$eb:ident] [$shb:ident]
+		{ $($arm:tt)* } { $($assert:tt)* }
+	) => {
+		$($assert)*
+
+		impl $crate::Engine for $engine {
+			type State = $state;
+			type Event = $event;
+			type Error = $error;
+			type Shared = $shared;
+
+			#[cfg(feature = "async")]
+			#[allow(unused)]
+			async fn next(
+				$sb: &Self::State,
+				$eb: Self::Event,
+				$shb: &mut Self::Shared,
+			) -> $crate::Flow<Self::State, Self::Error, Self::Event> {
+				match ($sb, $eb) {
+					$($arm)*
+					_ => $crate::Flow::Pass,
+				}
+			}
+
+			#[cfg(not(feature = "async"))]
+			#[allow(unused)]
+			fn next(
+				$sb: &Self::State,
+				$eb: Self::Event,
+				$shb: &mut Self::Shared,
+			) -> $crate::Flow<Self::State, Self::Error, Self::Event> {
+				match ($sb, $eb) {
+					$($arm)*
+					_ => $crate::Flow::Pass,
+				}
+			}
+		}
+	},
+
+	// Bare target state -> `Flow::Transition`. Tried before the expression
+	// rule so `=> Entry` is a transition, not a (mistyped) expression.
+	(
+		@munch $eng:tt $st:tt $ev:tt $er:tt $sh:tt $sbg:tt $ebg:tt $shbg:tt
+		{ $($arm:tt)* } { $($assert:tt)* }
+		$from:ident + $pat:pat $(if $guard:expr)? => $target:ident $(, $($rest:tt)*)?
+	) => {
+		$crate::state_machine! {
+			@munch $eng $st $ev $er $sh $sbg $ebg $shbg
+			{
+				$($arm)*
+				($from, $pat) $(if $guard)? => $crate::Flow::Transition($target),
+			}
+			{
+				$($assert)*
+				$crate::state_machine!(@assert $st, $from);
+				$crate::state_machine!(@assert $st, $target);
+			}
+			$($($rest)*)?
+		}
+	},
+
+	// Arbitrary expression action evaluating to a `Flow`.
+	(
+		@munch $eng:tt $st:tt $ev:tt $er:tt $sh:tt $sbg:tt $ebg:tt $shbg:tt
+		{ $($arm:tt)* } { $($assert:tt)* }
+		$from:ident + $pat:pat $(if $guard:expr)? => $action:expr $(, $($rest:tt)*)?
+	) => {
+		$crate::state_machine! {
+			@munch $eng $st $ev $er $sh $sbg $ebg $shbg
+			{
+				$($arm)*
+				($from, $pat) $(if $guard)? => $action,
+			}
+			{
+				$($assert)*
+				$crate::state_machine!(@assert $st, $from);
+			}
+			$($($rest)*)?
+		}
+	},
+
+	// Assert that an identifier names a real state of the machine.
+	(@assert [$state:ident], $variant:ident) => {
+		const _: fn() = || {
+			let _: $state = $state::$variant;
+		};
+	},
+}
+
+/// Bound for values recorded into [`tracing`] spans by [`Machine::fire`].
+///
+/// With the `tracing` feature it is an alias for [`Debug`], so states and events
+/// can be rendered into span fields; without the feature it is vacuous and adds
+/// no requirement to the public API.
+#[cfg(feature = "tracing")]
+pub trait Recordable: std::fmt::Debug {}
+#[cfg(feature = "tracing")]
+impl<T: std::fmt::Debug> Recordable for T {}
+#[cfg(not(feature = "tracing"))]
+pub trait Recordable {}
+#[cfg(not(feature = "tracing"))]
+impl<T> Recordable for T {}
+
 #[derive(Debug, Default)]
 struct Store<T, S> {
 	state: Mutex<T>,
@@ -98,14 +300,101 @@ impl<T, S> Store<T, S> {
 	}
 }
 
+/// A queued follow-up event waiting to be dispatched by [`Machine::fire`].
+///
+/// Ordered by [`Priority`] (highest first); ties break on the monotonic `seq`
+/// so that equal-priority events keep their enqueue order (FIFO). This makes the
+/// work-queue a max-[`BinaryHeap`](std::collections::BinaryHeap) while still
+/// being stable within a priority level.
+struct Pending<B> {
+	priority: Priority,
+	seq: u64,
+	event: B,
+	/// Span of the transition that enqueued this event, so its own `transition`
+	/// span can be opened as a child and a `Slide`/`Dispatch` cascade still reads
+	/// as one tree.
+	#[cfg(feature = "tracing")]
+	parent: tracing::Span,
+}
+
+impl<B> PartialEq for Pending<B> {
+	fn eq(&self, other: &Self) -> bool {
+		self.priority == other.priority && self.seq == other.seq
+	}
+}
+
+impl<B> Eq for Pending<B> {}
+
+impl<B> PartialOrd for Pending<B> {
+	fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl<B> Ord for Pending<B> {
+	fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+		// Higher priority wins; for equal priority the smaller `seq` (enqueued
+		// earlier) must pop first, so it compares as greater in this max-heap.
+		self.priority
+			.cmp(&other.priority)
+			.then_with(|| other.seq.cmp(&self.seq))
+	}
+}
+
+/// A checkpoint hook invoked with the fresh state and shared data after every
+/// successful transition (see [`Machine::with_checkpoint`]).
+type Checkpoint<E> = dyn Fn(&<E as Engine>::State, &<E as Engine>::Shared);
+
+/// A serializable picture of a [`Machine`] at rest: its current state and shared
+/// data, as produced by [`Machine::snapshot`] and consumed by
+/// [`Machine::restore`]/[`Machine::load`].
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(bound(
+	serialize = "E::State: serde::Serialize, E::Shared: serde::Serialize",
+	deserialize = "E::State: serde::Deserialize<'de>, E::Shared: serde::Deserialize<'de>"
+))]
+pub struct Snapshot<E: Engine> {
+	/// The state the machine was in when the snapshot was taken.
+	pub state: E::State,
+	/// The shared data accompanying that state.
+	pub shared: E::Shared,
+}
+
 /// `Machine` is a struct that encapsulates the state and shared data of the state machine,
 /// providing methods to advance the state based on events.
-#[derive(Debug, Clone)]
 pub struct Machine<E: Engine> {
 	store: Arc<Store<E::State, E::Shared>>,
+	checkpoint: Option<Arc<Checkpoint<E>>>,
 	event: PhantomData<E::Event>,
 }
 
+// Hand-written so cloning a `Machine` never requires `E: Clone`: every field is
+// a cheap handle (`Arc`/`Option<Arc<…>>`/`PhantomData`), and clones share the
+// same `Store`, so two handles observe the same state.
+impl<E: Engine> Clone for Machine<E> {
+	fn clone(&self) -> Self {
+		Self {
+			store: Arc::clone(&self.store),
+			checkpoint: self.checkpoint.clone(),
+			event: PhantomData,
+		}
+	}
+}
+
+impl<E: Engine> std::fmt::Debug for Machine<E>
+where
+	E::State: std::fmt::Debug,
+	E::Shared: std::fmt::Debug,
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("Machine")
+			.field("store", &self.store)
+			.field("checkpoint", &self.checkpoint.is_some())
+			.finish()
+	}
+}
+
 impl<E: Engine> Default for Machine<E>
 where
 	E::State: Default,
@@ -114,6 +403,7 @@ where
 	fn default() -> Self {
 		Self {
 			store: Default::default(),
+			checkpoint: None,
 			event: Default::default(),
 		}
 	}
@@ -127,6 +417,7 @@ impl<E: Engine> Machine<E> {
 	{
 		Self {
 			store: Arc::new(Store::new(initial, E::Shared::default())),
+			checkpoint: None,
 			event: Default::default(),
 		}
 	}
@@ -135,6 +426,7 @@ impl<E: Engine> Machine<E> {
 	pub fn new_shared(initial: E::State, shared: E::Shared) -> Self {
 		Self {
 			store: Arc::new(Store::new(initial, shared)),
+			checkpoint: None,
 			event: Default::default(),
 		}
 	}
@@ -146,47 +438,314 @@ impl<E: Engine> Machine<E> {
 	{
 		Self {
 			store: Arc::new(Store::new(E::State::default(), shared)),
+			checkpoint: None,
 			event: Default::default(),
 		}
 	}
 
+	/// Registers a checkpoint hook invoked after every successful transition.
+	///
+	/// Once set, [`fire`](Self::fire) calls `f` with the new state and shared
+	/// data right after each [`set_state`](Self::set_state), so
+	/// callers can durably record progress (to disk or a db) and recover it with
+	/// [`restore`](Self::restore)/[`load`](Self::load) after a restart.
+	pub fn with_checkpoint(mut self, f: impl Fn(&E::State, &E::Shared) + 'static) -> Self {
+		self.checkpoint = Some(Arc::new(f));
+		self
+	}
+
 	fn set_state(&self, new_state: E::State) {
 		let mut state = self.store.state.lock().unwrap();
 		*state = new_state;
+		if let Some(checkpoint) = &self.checkpoint {
+			let shared = self.store.shared.lock().unwrap();
+			checkpoint(&state, &shared);
+		}
 	}
 
-	fn infer_result(&self, flow: Flow<E::State, E::Error, E::Event>) -> Result<(), E::Error> {
+	/// Applies a single [`Flow`] to `queue`, recording the outcome into the
+	/// transition's `span` and enqueueing any follow-up events.
+	///
+	/// Returns the error of a [`Flow::Failure`] so the caller can stop draining;
+	/// every other flow returns `Ok(())`. Follow-ups are tagged with `seq` for a
+	/// stable FIFO order within a priority and, under the `tracing` feature,
+	/// parented to `span` so a cascade reads as one tree.
+	fn apply(
+		&self,
+		flow: Flow<E::State, E::Error, E::Event>,
+		queue: &mut BinaryHeap<Pending<E::Event>>,
+		seq: &mut u64,
+		#[cfg(feature = "tracing")] span: &tracing::Span,
+	) -> Result<(), E::Error>
+	where
+		E::State: Recordable,
+	{
+		let mut enqueue = |priority, event| {
+			*seq += 1;
+			queue.push(Pending {
+				priority,
+				seq: *seq,
+				event,
+				#[cfg(feature = "tracing")]
+				parent: span.clone(),
+			});
+		};
+
 		match flow {
-			Flow::Pass => Ok(()),
+			Flow::Pass => {
+				#[cfg(feature = "tracing")]
+				span.record("flow", "Pass");
+			}
 			Flow::Transition(new_state) => {
+				#[cfg(feature = "tracing")]
+				{
+					span.record("flow", "Transition");
+					span.record("to_state", tracing::field::debug(&new_state));
+				}
 				self.set_state(new_state);
-				Ok(())
 			}
 			Flow::Slide(new_state, event) => {
+				#[cfg(feature = "tracing")]
+				{
+					span.record("flow", "Slide");
+					span.record("to_state", tracing::field::debug(&new_state));
+				}
+				self.set_state(new_state);
+				enqueue(0, event);
+			}
+			Flow::Dispatch(new_state, events) => {
+				#[cfg(feature = "tracing")]
+				{
+					span.record("flow", "Dispatch");
+					span.record("to_state", tracing::field::debug(&new_state));
+				}
 				self.set_state(new_state);
-				self.fire(event)
+				for (priority, event) in events {
+					enqueue(priority, event);
+				}
+			}
+			Flow::Failure(err) => {
+				#[cfg(feature = "tracing")]
+				span.record("flow", "Failure");
+				return Err(err);
 			}
-			Flow::Failure(err) => Err(err),
 		}
+
+		Ok(())
 	}
 
 	/// Fires the specified event on the state machine to advance the state asynchronously.
 	#[cfg(feature = "async")]
-	pub async fn fire(&self, event: E::Event) -> Result<(), E::Error> {
-		self.infer_result({
-			let state = self.store.state.lock().unwrap();
-			let mut shared = self.store.shared.lock().unwrap();
-			E::next(&state, event, &mut shared).await
-		})
+	pub async fn fire(&self, event: E::Event) -> Result<(), E::Error>
+	where
+		E::State: Recordable,
+		E::Event: Recordable,
+	{
+		let mut queue = BinaryHeap::new();
+		let mut seq = 0u64;
+		queue.push(Pending {
+			priority: 0,
+			seq,
+			event,
+			#[cfg(feature = "tracing")]
+			parent: tracing::Span::current(),
+		});
+
+		// Drain the queue highest-priority-first. Follow-ups enqueued by a
+		// transition are processed before control returns to the caller; a
+		// `Flow::Failure` drops the queue, clearing every pending event.
+		while let Some(item) = queue.pop() {
+			#[cfg(feature = "tracing")]
+			let parent = item.parent;
+			let event = item.event;
+
+			#[cfg(feature = "tracing")]
+			let span = tracing::info_span!(
+				parent: &parent,
+				"transition",
+				from_state = ?*self.store.state.lock().unwrap(),
+				event = ?event,
+				to_state = tracing::field::Empty,
+				flow = tracing::field::Empty,
+			);
+
+			let next = async {
+				let state = self.store.state.lock().unwrap();
+				let mut shared = self.store.shared.lock().unwrap();
+				E::next(&state, event, &mut shared).await
+			};
+
+			// Instrument only the `next` future so no `enter` guard is held across
+			// the `await`; recording and enqueueing happen synchronously afterwards.
+			#[cfg(feature = "tracing")]
+			let flow = {
+				use tracing::Instrument;
+				next.instrument(span.clone()).await
+			};
+			#[cfg(not(feature = "tracing"))]
+			let flow = next.await;
+
+			self.apply(
+				flow,
+				&mut queue,
+				&mut seq,
+				#[cfg(feature = "tracing")]
+				&span,
+			)?;
+		}
+
+		Ok(())
 	}
 
 	/// Fires the specified event on the state machine to advance the state.
 	#[cfg(not(feature = "async"))]
-	pub fn fire(&self, event: E::Event) -> Result<(), E::Error> {
-		self.infer_result({
-			let state = self.store.state.lock().unwrap();
-			let mut shared = self.store.shared.lock().unwrap();
-			E::next(&state, event, &mut shared)
-		})
+	pub fn fire(&self, event: E::Event) -> Result<(), E::Error>
+	where
+		E::State: Recordable,
+		E::Event: Recordable,
+	{
+		let mut queue = BinaryHeap::new();
+		let mut seq = 0u64;
+		queue.push(Pending {
+			priority: 0,
+			seq,
+			event,
+			#[cfg(feature = "tracing")]
+			parent: tracing::Span::current(),
+		});
+
+		// Drain the queue highest-priority-first. Follow-ups enqueued by a
+		// transition are processed before control returns to the caller; a
+		// `Flow::Failure` drops the queue, clearing every pending event.
+		while let Some(item) = queue.pop() {
+			#[cfg(feature = "tracing")]
+			let parent = item.parent;
+			let event = item.event;
+
+			#[cfg(feature = "tracing")]
+			let span = tracing::info_span!(
+				parent: &parent,
+				"transition",
+				from_state = ?*self.store.state.lock().unwrap(),
+				event = ?event,
+				to_state = tracing::field::Empty,
+				flow = tracing::field::Empty,
+			);
+			#[cfg(feature = "tracing")]
+			let _entered = span.enter();
+
+			let flow = {
+				let state = self.store.state.lock().unwrap();
+				let mut shared = self.store.shared.lock().unwrap();
+				E::next(&state, event, &mut shared)
+			};
+
+			self.apply(
+				flow,
+				&mut queue,
+				&mut seq,
+				#[cfg(feature = "tracing")]
+				&span,
+			)?;
+		}
+
+		Ok(())
+	}
+
+	/// Drives the machine as a long-lived actor, consuming events from a stream in order.
+	///
+	/// Each event is `fire`d as it arrives; the first one that yields a
+	/// [`Flow::Failure`] stops the run and propagates its error, exactly like a
+	/// synchronous sequence of [`fire`](Self::fire) calls would.
+	#[cfg(feature = "async")]
+	pub async fn run<S>(self, events: S) -> Result<(), E::Error>
+	where
+		S: Stream<Item = E::Event>,
+		E::State: Recordable,
+		E::Event: Recordable,
+	{
+		futures::pin_mut!(events);
+		while let Some(event) = events.next().await {
+			self.fire(event).await?;
+		}
+		Ok(())
+	}
+
+	/// Drives the machine by `fire`ing every event of `iter` in order.
+	///
+	/// The non-async companion to [`run`](Self::run): it stops and returns the
+	/// error of the first event that yields a [`Flow::Failure`].
+	#[cfg(not(feature = "async"))]
+	pub fn drive(&self, iter: impl IntoIterator<Item = E::Event>) -> Result<(), E::Error>
+	where
+		E::State: Recordable,
+		E::Event: Recordable,
+	{
+		for event in iter {
+			self.fire(event)?;
+		}
+		Ok(())
+	}
+
+	/// Returns a handle that feeds events into the machine from anywhere.
+	///
+	/// The returned [`Sender`](futures::channel::mpsc::UnboundedSender) can be
+	/// cloned and moved into a `Subscriber`-style callback to `send` events as
+	/// they happen, while the returned future owns the ordering: it drains the
+	/// channel and [`fire`](Self::fire)s each event in the order it was sent,
+	/// finishing when every sender is dropped or the first event fails.
+	#[cfg(feature = "async")]
+	pub fn initiator(
+		&self,
+	) -> (
+		futures::channel::mpsc::UnboundedSender<E::Event>,
+		impl Future<Output = Result<(), E::Error>>,
+	)
+	where
+		E::State: Recordable,
+		E::Event: Recordable,
+	{
+		let (sender, receiver) = futures::channel::mpsc::unbounded();
+		(sender, self.clone().run(receiver))
+	}
+
+	/// Captures the machine's current state and shared data as a [`Snapshot`].
+	///
+	/// The snapshot is an owned, serializable copy, so it can be written out and
+	/// later fed back to [`restore`](Self::restore) or [`load`](Self::load).
+	#[cfg(feature = "serde")]
+	pub fn snapshot(&self) -> Snapshot<E>
+	where
+		E::State: Clone + serde::Serialize,
+		E::Shared: Clone + serde::Serialize,
+	{
+		Snapshot {
+			state: self.store.state.lock().unwrap().clone(),
+			shared: self.store.shared.lock().unwrap().clone(),
+		}
+	}
+
+	/// Builds a fresh `Machine` from a previously taken [`Snapshot`].
+	///
+	/// The restored machine starts without a checkpoint hook; attach one again
+	/// with [`with_checkpoint`](Self::with_checkpoint) if needed.
+	#[cfg(feature = "serde")]
+	pub fn restore(snapshot: Snapshot<E>) -> Self {
+		Self {
+			store: Arc::new(Store::new(snapshot.state, snapshot.shared)),
+			checkpoint: None,
+			event: PhantomData,
+		}
+	}
+
+	/// Overwrites this machine's state and shared data from a [`Snapshot`] in place.
+	///
+	/// Unlike [`restore`](Self::restore) this keeps the existing handle (and any
+	/// registered checkpoint hook), so clones made with [`Clone`] see the loaded
+	/// data too.
+	#[cfg(feature = "serde")]
+	pub fn load(&self, snapshot: Snapshot<E>) {
+		*self.store.state.lock().unwrap() = snapshot.state;
+		*self.store.shared.lock().unwrap() = snapshot.shared;
 	}
 }